@@ -0,0 +1,126 @@
+//! PIO/MMIO总线：把端口/地址范围路由到具体的设备模型，取代以前
+//! 在`run`循环里对所有`IoOut`一律当字符串打印的做法
+
+use std::ops::Range;
+
+/// 挂在总线上的设备需要实现的读写接口，`offset`是相对设备基址的偏移
+pub trait Device {
+    fn read(&mut self, offset: u64, data: &mut [u8]);
+    fn write(&mut self, offset: u64, data: &[u8]);
+}
+
+struct BusDevice {
+    range: Range<u64>,
+    device: Box<dyn Device + Send>,
+}
+
+/// 按地址范围分发读写请求的总线，PIO端口和MMIO地址各用一条
+#[derive(Default)]
+pub struct Bus {
+    devices: Vec<BusDevice>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus {
+            devices: Vec::new(),
+        }
+    }
+
+    /// 把一个设备挂在`[base, base+size)`这段地址范围上
+    pub fn register(&mut self, base: u64, size: u64, device: Box<dyn Device + Send>) {
+        self.devices.push(BusDevice {
+            range: base..base + size,
+            device,
+        });
+    }
+
+    pub fn read(&mut self, addr: u64, data: &mut [u8]) {
+        if let Some(bd) = self.devices.iter_mut().find(|d| d.range.contains(&addr)) {
+            let offset = addr - bd.range.start;
+            bd.device.read(offset, data);
+        }
+    }
+
+    pub fn write(&mut self, addr: u64, data: &[u8]) {
+        if let Some(bd) = self.devices.iter_mut().find(|d| d.range.contains(&addr)) {
+            let offset = addr - bd.range.start;
+            bd.device.write(offset, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// 把收到的读写请求的offset记到共享状态里，这样注册进`Bus`（从而被
+    /// type-erase成`Box<dyn Device>`）之后，测试还是能看到它收到了什么
+    #[derive(Clone, Default)]
+    struct RecordingDevice {
+        last_read_offset: Arc<Mutex<Option<u64>>>,
+        last_write: Arc<Mutex<Option<(u64, u8)>>>,
+    }
+
+    impl Device for RecordingDevice {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            *self.last_read_offset.lock().unwrap() = Some(offset);
+            data[0] = 0x42;
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) {
+            *self.last_write.lock().unwrap() = Some((offset, data[0]));
+        }
+    }
+
+    #[test]
+    fn dispatches_to_the_device_whose_range_contains_the_address() {
+        let com1 = RecordingDevice::default();
+        let keyboard = RecordingDevice::default();
+        let mut bus = Bus::new();
+        bus.register(0x3f8, 8, Box::new(com1.clone()));
+        bus.register(0x60, 4, Box::new(keyboard.clone()));
+
+        let mut data = [0u8; 1];
+        bus.write(0x3fa, &[7]);
+        bus.read(0x62, &mut data);
+
+        assert_eq!(*com1.last_write.lock().unwrap(), Some((2, 7)));
+        assert_eq!(*keyboard.last_read_offset.lock().unwrap(), Some(2));
+        assert_eq!(data[0], 0x42);
+        // 没被寻址到的设备不应该被碰到
+        assert!(com1.last_read_offset.lock().unwrap().is_none());
+        assert!(keyboard.last_write.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn offset_is_relative_to_each_devices_own_base_address() {
+        let low = RecordingDevice::default();
+        let high = RecordingDevice::default();
+        let mut bus = Bus::new();
+        bus.register(0x100, 4, Box::new(low.clone()));
+        bus.register(0x200, 4, Box::new(high.clone()));
+
+        bus.write(0x202, &[1]);
+
+        assert_eq!(*high.last_write.lock().unwrap(), Some((2, 1)));
+        assert!(low.last_write.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn addresses_outside_every_range_are_silently_dropped() {
+        let device = RecordingDevice::default();
+        let mut bus = Bus::new();
+        bus.register(0x3f8, 8, Box::new(device.clone()));
+
+        let mut data = [0xffu8; 1];
+        bus.read(0x1000, &mut data);
+        bus.write(0x1000, &[5]);
+
+        // 没有设备覆盖这个地址，读写都不应该落地
+        assert_eq!(data[0], 0xff);
+        assert!(device.last_read_offset.lock().unwrap().is_none());
+        assert!(device.last_write.lock().unwrap().is_none());
+    }
+}
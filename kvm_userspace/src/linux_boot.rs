@@ -0,0 +1,94 @@
+//! 用`linux-loader`加载一个真正的bzImage，并按照x86的64位引导协议
+//! 搭建zero page（`boot_params`），取代手写`kernel.bin`那种直接把代码拍到
+//! 物理地址0的做法。做法与cloud-hypervisor的`vm.rs`一致。
+
+use std::fs::File;
+
+use linux_loader::bootparam::boot_params;
+use linux_loader::cmdline::Cmdline;
+use linux_loader::configurator::linux::LinuxBootConfigurator;
+use linux_loader::configurator::{BootConfigurator, BootParams};
+use linux_loader::loader::bzimage::BzImage;
+use linux_loader::loader::{KernelLoader, KernelLoaderResult};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryBackend, GuestMemoryMmap, GuestMemoryRegion};
+
+/// 默认的内核命令行，走串口控制台
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0,115200n8";
+/// 命令行字符串在客户机内存中的固定偏移
+pub const CMDLINE_START: u64 = 0x20000;
+/// zero page自身的固定偏移
+pub const ZERO_PAGE_START: u64 = 0x7000;
+/// E820 RAM类型
+const E820_RAM: u32 = 1;
+/// bzImage 64位入口相对`kernel_load`的偏移，见Linux boot协议文档中
+/// "the 32-bit boot protocol" 之后紧跟的64位入口点
+const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
+
+/// 加载bzImage并搭建好zero page，返回内核入口地址（写入`rip`）
+/// 以及zero page地址（写入`rsi`，这是64位引导协议约定的入参寄存器）
+pub fn load_bzimage(
+    guest_mem: &GuestMemoryMmap,
+    image: &mut File,
+    himem_start: GuestAddress,
+    cmdline: Option<&str>,
+) -> (GuestAddress, GuestAddress) {
+    let kernel_load_result: KernelLoaderResult =
+        BzImage::load(guest_mem, None, image, Some(himem_start)).expect("load bzImage failed");
+
+    let mut cmdline_obj = Cmdline::new(4096).expect("failed to allocate cmdline buffer");
+    cmdline_obj
+        .insert_str(cmdline.unwrap_or(DEFAULT_CMDLINE))
+        .expect("cmdline too long");
+    let cmdline_cstring = cmdline_obj.as_cstring().expect("invalid cmdline");
+    guest_mem
+        .write_slice(
+            cmdline_cstring.as_bytes_with_nul(),
+            GuestAddress(CMDLINE_START),
+        )
+        .expect("failed to write cmdline to guest memory");
+
+    let mut params = boot_params::default();
+    if let Some(hdr) = kernel_load_result.setup_header {
+        params.hdr = hdr;
+    }
+    params.hdr.vid_mode = 0xffff; // VGA_NORMAL
+    params.hdr.type_of_loader = 0xff; // 未知的引导加载器
+    params.hdr.cmd_line_ptr = CMDLINE_START as u32;
+    params.hdr.cmdline_size = cmdline_cstring.as_bytes_with_nul().len() as u32;
+
+    // 每个客户机内存区域各生成一条E820条目，这样setup_memory按chunk0-5
+    // 配置出来的洞（比如640KiB-1MiB）会如实反映给客户机，而不是假装成
+    // 一整块连续RAM
+    for region in guest_mem.iter() {
+        add_e820_entry(
+            &mut params,
+            region.start_addr().raw_value(),
+            region.len(),
+            E820_RAM,
+        );
+    }
+
+    let zero_page_addr = GuestAddress(ZERO_PAGE_START);
+    LinuxBootConfigurator::write_bootparams(&BootParams::new(&params, zero_page_addr), guest_mem)
+        .expect("failed to write zero page");
+
+    // bzImage的`kernel_load`是32位入口；64位引导协议的入口在其后0x200字节处
+    let entry = if kernel_load_result.setup_header.is_some() {
+        kernel_load_result
+            .kernel_load
+            .checked_add(KERNEL_64BIT_ENTRY_OFFSET)
+            .expect("kernel entry address overflow")
+    } else {
+        kernel_load_result.kernel_load
+    };
+
+    (entry, zero_page_addr)
+}
+
+fn add_e820_entry(params: &mut boot_params, addr: u64, size: u64, mem_type: u32) {
+    let entry_idx = params.e820_entries as usize;
+    params.e820_table[entry_idx].addr = addr;
+    params.e820_table[entry_idx].size = size;
+    params.e820_table[entry_idx].r#type = mem_type;
+    params.e820_entries += 1;
+}
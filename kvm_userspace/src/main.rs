@@ -1,155 +1,416 @@
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
-use kvm_bindings::{kvm_regs, kvm_sregs, kvm_userspace_memory_region};
+use kvm_bindings::{kvm_regs, kvm_segment, kvm_sregs};
 use kvm_ioctls::{Kvm, VcpuFd, VmFd};
-use libc::{c_void, mmap, MAP_ANONYMOUS, MAP_SHARED, PROT_READ, PROT_WRITE};
+use vm_memory::GuestAddress;
+
+mod bus;
+mod linux_boot;
+mod memory;
+mod serial;
+
+use bus::Bus;
+use memory::{GuestMemoryManager, GuestRegionDesc};
+use serial::Serial;
+
+// COM1的PIO地址范围
+const COM1_BASE: u64 = 0x3f8;
+const COM1_SIZE: u64 = 8;
 
 extern crate kvm_bindings;
 extern crate kvm_ioctls;
 extern crate libc;
+extern crate linux_loader;
+extern crate vm_memory;
+
+// cr0/cr4/efer位，用于从实模式切换到64位长模式
+const CR0_PE: u64 = 1 << 0;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PAE: u64 = 1 << 5;
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+
+// 页表项/GDT在客户机物理内存中的固定位置
+const PML4_ADDR: u64 = 0x1000;
+const PDPT_ADDR: u64 = 0x2000;
+const PD_ADDR: u64 = 0x3000;
+const GDT_ADDR: u64 = 0x4000;
+
+const PAGE_PRESENT: u64 = 1 << 0;
+const PAGE_RW: u64 = 1 << 1;
+const PAGE_PS: u64 = 1 << 7;
+
+/// vCPU启动时所处的模式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BootMode {
+    /// 16位实模式，cs.base=0，不开启分页，只能跑kernel.bin这样的小程序
+    Real,
+    /// 64位长模式，预先建好恒等映射的4级页表和平坦GDT
+    Long,
+}
 
 struct Vm {
+    /// 只是为了让`/dev/kvm`句柄跟`Vm`活得一样久而保留，本身不会再被读取
+    #[allow(dead_code)]
     kvm: Kvm,
     vm: VmFd,
-    hva_ram_start: usize,
-    vcpu: Option<VcpuFd>,
+    memory: Option<Arc<GuestMemoryManager>>,
+    vcpus: Vec<VcpuFd>,
+    num_vcpus: usize,
+    boot_mode: BootMode,
+    /// PIO端口总线，`IoIn`/`IoOut`路由到这里（目前挂了COM1串口）
+    pio_bus: Arc<Mutex<Bus>>,
+    /// MMIO地址总线，`MmioRead`/`MmioWrite`路由到这里，供未来的virtio-mmio
+    /// 之类的设备挂载
+    mmio_bus: Arc<Mutex<Bus>>,
 }
 
 impl Vm {
-    pub fn new() -> Self {
+    pub fn new(boot_mode: BootMode, num_vcpus: usize) -> Self {
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
         Vm {
             kvm,
             vm,
-            hva_ram_start: 0,
-            vcpu: None,
+            memory: None,
+            vcpus: Vec::new(),
+            num_vcpus,
+            boot_mode,
+            pio_bus: Arc::new(Mutex::new(Bus::new())),
+            mmio_bus: Arc::new(Mutex::new(Bus::new())),
         }
     }
 
-    fn setup_memory(&mut self, ram_size: usize) {
-        println!("setup_memory");
-        // 把大小按照4096对齐
-        let ram_size = (ram_size + 0xfff) & !0xfff;
-
-        // 使用mmap分配虚拟机的内存
-        let ptr = unsafe {
-            mmap(
-                0 as *mut c_void,
-                ram_size,
-                PROT_READ | PROT_WRITE,
-                MAP_SHARED | MAP_ANONYMOUS,
-                -1,
-                0,
-            )
+    /// 把串口等设备挂到PIO总线上，PIO端口由`run_vcpu`里的`IoIn`/`IoOut`转发过来。
+    /// `interactive_console`只应该在确实有人在终端前跟客户机交互时打开
+    /// （比如真正的bzImage配上`console=ttyS0`）——它会把host stdin切到raw
+    /// 模式并桥接进串口的RX FIFO；非交互的`kernel.bin`演示路径不需要，也不
+    /// 应该去碰host的终端
+    fn setup_devices(&mut self, interactive_console: bool) {
+        let serial = if interactive_console {
+            Serial::new().with_stdin_bridge()
+        } else {
+            Serial::new()
         };
-        if ptr == libc::MAP_FAILED {
-            panic!("mmap failed");
+        self.pio_bus
+            .lock()
+            .unwrap()
+            .register(COM1_BASE, COM1_SIZE, Box::new(serial));
+    }
+
+    /// 按给定的(guest_phys_addr, size)区域列表建立客户机内存布局，每个
+    /// 区域各自注册为一个KVM内存slot，取代之前单个flat size的假设
+    fn setup_memory(&mut self, regions: &[GuestRegionDesc]) {
+        println!("setup_memory");
+        let manager = GuestMemoryManager::new(regions);
+        manager.register_with_kvm(&self.vm);
+        self.memory = Some(Arc::new(manager));
+    }
+
+    fn memory(&self) -> &GuestMemoryManager {
+        self.memory.as_ref().expect("setup_memory not called yet")
+    }
+
+    /// 把全部客户机内存拍到`path`，作为后续增量快照的基线
+    fn snapshot_ram(&self, path: &std::path::Path) {
+        self.memory().snapshot_ram(path);
+    }
+
+    /// 只把dirty log标记过的4KiB页写回`path`指向的既有快照文件
+    fn snapshot_ram_incremental(&self, path: &std::path::Path) {
+        self.memory().snapshot_ram_incremental(&self.vm, path);
+    }
+
+    /// 在客户机内存里搭建恒等映射的4级页表（PML4 -> PDPT -> PD，PD用2MiB大页）
+    /// 以及一份平坦的GDT，所有vCPU共用这一份
+    fn setup_long_mode_memory(&mut self) {
+        let mem = self.memory();
+        // PML4[0] -> PDPT
+        mem.write_obj(PDPT_ADDR | PAGE_PRESENT | PAGE_RW, GuestAddress(PML4_ADDR))
+            .expect("failed to write PML4");
+        // PDPT[0] -> PD
+        mem.write_obj(PD_ADDR | PAGE_PRESENT | PAGE_RW, GuestAddress(PDPT_ADDR))
+            .expect("failed to write PDPT");
+        // PD: 512个2MiB大页，恒等映射前1GiB
+        for i in 0..512u64 {
+            let entry = (i * 0x200000) | PAGE_PRESENT | PAGE_RW | PAGE_PS;
+            mem.write_obj(entry, GuestAddress(PD_ADDR + i * 8))
+                .expect("failed to write PD entry");
         }
 
-        self.hva_ram_start = ptr as usize;
+        // 平坦GDT：null、64位代码段、数据段
+        mem.write_obj(0u64, GuestAddress(GDT_ADDR))
+            .expect("failed to write GDT null descriptor");
+        mem.write_obj(0x00af9b000000ffffu64, GuestAddress(GDT_ADDR + 8)) // code: L=1,G=1,type=0b1011
+            .expect("failed to write GDT code descriptor");
+        mem.write_obj(0x00cf93000000ffffu64, GuestAddress(GDT_ADDR + 16)) // data: G=1, writable, flat
+            .expect("failed to write GDT data descriptor");
+    }
 
-        // 设置虚拟机的内存，相当于插入1个内存条
-        // 插槽编号为0，物理地址从0开始，大小为ram_size
+    /// 配置某个vCPU的sregs使其运行在64位长模式：分页、PAE、长模式位，
+    /// 以及指向恒等映射页表和平坦GDT的cs/ds段描述符
+    fn configure_long_mode_sregs(vcpu: &VcpuFd) {
+        let mut sregs: kvm_sregs = vcpu.get_sregs().expect("get sregs failed");
 
-        let mem_region = kvm_userspace_memory_region {
-            slot: 0,
-            guest_phys_addr: 0 as u64,
-            memory_size: ram_size as u64,
-            userspace_addr: ptr as u64,
-            flags: 0,
+        sregs.cr3 = PML4_ADDR;
+        sregs.cr4 |= CR4_PAE;
+        sregs.cr0 |= CR0_PE | CR0_PG;
+        sregs.efer |= EFER_LME | EFER_LMA;
+
+        sregs.gdt.base = GDT_ADDR;
+        sregs.gdt.limit = 23; // 3个描述符，每个8字节
+
+        let code_seg = kvm_segment {
+            base: 0,
+            limit: 0xffffffff,
+            selector: 1 << 3,
+            type_: 0b1011,
+            present: 1,
+            dpl: 0,
+            db: 0,
+            s: 1,
+            l: 1,
+            g: 1,
+            avl: 0,
+            unusable: 0,
+            padding: 0,
         };
-        unsafe {
-            self.vm
-                .set_user_memory_region(mem_region)
-                .map_err(|e| panic!("set_user_memory_region failed: {:?}", e))
-                .unwrap();
+        sregs.cs = code_seg;
+
+        let data_seg = kvm_segment {
+            base: 0,
+            limit: 0xffffffff,
+            selector: 2 << 3,
+            type_: 0b0011,
+            present: 1,
+            dpl: 0,
+            db: 1,
+            s: 1,
+            l: 0,
+            g: 1,
+            avl: 0,
+            unusable: 0,
+            padding: 0,
         };
+        sregs.ds = data_seg;
+        sregs.es = data_seg;
+        sregs.fs = data_seg;
+        sregs.gs = data_seg;
+        sregs.ss = data_seg;
+
+        vcpu.set_sregs(&sregs).expect("set sregs failed");
     }
 
+    /// 按`boot_mode`配置一个刚创建出来的vCPU的寄存器
+    fn configure_vcpu(&self, vcpu: &VcpuFd) {
+        match self.boot_mode {
+            BootMode::Real => {
+                // 保持16位实模式，cs.base=0
+                let mut sregs: kvm_sregs = vcpu.get_sregs().expect("get sregs failed");
+                sregs.cs.selector = 0;
+                sregs.cs.base = 0;
+                vcpu.set_sregs(&sregs).expect("set sregs failed");
+            }
+            BootMode::Long => {
+                Self::configure_long_mode_sregs(vcpu);
+            }
+        }
+
+        let mut regs: kvm_regs = vcpu.get_regs().expect("get regs failed");
+        regs.rax = 0;
+        regs.rbx = 0;
+        regs.rip = 0;
+        if self.boot_mode == BootMode::Long {
+            // 进入长模式后，rflags里的保留位(bit1)必须为1
+            regs.rflags = 0x2;
+        }
+        vcpu.set_regs(&regs).unwrap();
+    }
+
+    /// 按`num_vcpus`创建每个vCPU（APIC id从0开始递增），并各自配置寄存器
     fn setup_cpu(&mut self) {
-        // 创建一个虚拟CPU
-        let vcpu = self.vm.create_vcpu(0).unwrap();
-        self.vcpu = Some(vcpu);
-        // 设置虚拟CPU的寄存器
-
-        let mut vcpu_sregs: kvm_sregs = self
-            .vcpu
-            .as_ref()
-            .unwrap()
-            .get_sregs()
-            .expect("get sregs failed");
-        vcpu_sregs.cs.selector = 0;
-        vcpu_sregs.cs.base = 0;
-        self.vcpu
-            .as_ref()
-            .unwrap()
-            .set_sregs(&vcpu_sregs)
-            .expect("set sregs failed");
+        if self.boot_mode == BootMode::Long {
+            self.setup_long_mode_memory();
+        }
 
-        let mut vcpu_regs: kvm_regs = self
-            .vcpu
-            .as_ref()
-            .unwrap()
-            .get_regs()
-            .expect("get regs failed");
-        vcpu_regs.rax = 0;
-        vcpu_regs.rbx = 0;
-        vcpu_regs.rip = 0;
-        self.vcpu.as_ref().unwrap().set_regs(&vcpu_regs).unwrap();
+        for apic_id in 0..self.num_vcpus {
+            let vcpu = self.vm.create_vcpu(apic_id as u64).unwrap();
+            self.configure_vcpu(&vcpu);
+            self.vcpus.push(vcpu);
+        }
     }
 
     fn load_image(&mut self, image: PathBuf) {
         println!("load_image");
-        // 读取kernel.bin文件
+        // 读取kernel.bin文件，写入客户机物理地址0处
         let kernel = std::fs::read(image).unwrap();
         println!("kernel: {:?}", kernel);
-        // 把kernel.bin文件写入虚拟机的内存
-        let ptr = (self.hva_ram_start) as *mut u8;
-        println!(
-            "self.hva_ram_start: {:p}, ptr={ptr:?}",
-            self.hva_ram_start as *mut u8
+        self.memory()
+            .write_slice(&kernel, GuestAddress(0))
+            .expect("failed to write kernel image to guest memory");
+    }
+
+    /// 加载一个真正的bzImage并搭建好64位引导协议需要的zero page，
+    /// 取代`load_image`那种把裸二进制拍到物理地址0的做法。入口寄存器
+    /// 写到第一个（引导）vCPU上
+    fn load_kernel(&mut self, bzimage: PathBuf, cmdline: Option<&str>) {
+        println!("load_kernel: {:?}", bzimage);
+        let mut image = std::fs::File::open(bzimage).unwrap();
+
+        let (entry, zero_page) = linux_boot::load_bzimage(
+            self.memory().inner(),
+            &mut image,
+            GuestAddress(0x100000),
+            cmdline,
         );
-        unsafe {
-            std::ptr::copy_nonoverlapping(kernel.as_ptr(), ptr, kernel.len());
-        }
+
+        let vcpu = &self.vcpus[0];
+        let mut regs: kvm_regs = vcpu.get_regs().expect("get regs failed");
+        regs.rip = entry.0;
+        regs.rsi = zero_page.0; // 64位引导协议：rsi指向zero page
+        regs.rflags = 0x2;
+        vcpu.set_regs(&regs).expect("set regs failed");
     }
 
-    fn run(&mut self) {
-        println!("run");
-        let vcpu = self.vcpu.as_mut().unwrap();
-        loop {
+    /// 在`FailEntry`/`InternalError`这类硬件报错退出时，把寄存器状态打印
+    /// 出来方便排查
+    fn dump_vcpu_state(id: usize, vcpu: &VcpuFd) {
+        println!("vcpu{id}: regs={:?}", vcpu.get_regs());
+        println!("vcpu{id}: sregs={:?}", vcpu.get_sregs());
+    }
+
+    /// 单个vCPU的运行循环，跑在它自己的线程里。PIO/MMIO分别通过各自的
+    /// 共享设备总线分发；任何一个vCPU触发`Shutdown`、三重故障或硬件报错都
+    /// 会置位`shutdown`。每个线程只在`vcpu.run()`返回后、进入下一轮循环前
+    /// 检查这个标志，不会把其他vCPU从`KVM_RUN`里强行踢出来
+    fn run_vcpu(
+        id: usize,
+        mut vcpu: VcpuFd,
+        pio_bus: Arc<Mutex<Bus>>,
+        mmio_bus: Arc<Mutex<Bus>>,
+        shutdown: Arc<AtomicBool>,
+    ) {
+        while !shutdown.load(Ordering::Relaxed) {
             match vcpu.run().expect("run failed") {
                 kvm_ioctls::VcpuExit::Hlt => {
-                    println!("KVM_EXIT_HLT");
+                    println!("vcpu{id}: KVM_EXIT_HLT");
                     // sleep 1s using rust std
                     std::thread::sleep(std::time::Duration::from_secs(1));
                 }
                 kvm_ioctls::VcpuExit::IoOut(port, data) => {
-                    let data_str = String::from_utf8_lossy(data);
-                    print!("{}", data_str);
+                    pio_bus.lock().unwrap().write(port as u64, data);
+                }
+                kvm_ioctls::VcpuExit::IoIn(port, data) => {
+                    pio_bus.lock().unwrap().read(port as u64, data);
+                }
+                kvm_ioctls::VcpuExit::MmioWrite(addr, data) => {
+                    mmio_bus.lock().unwrap().write(addr, data);
+                }
+                kvm_ioctls::VcpuExit::MmioRead(addr, data) => {
+                    mmio_bus.lock().unwrap().read(addr, data);
+                }
+                kvm_ioctls::VcpuExit::Shutdown => {
+                    // 客户机三重故障等场景会走到这里，属于正常关机，不是错误
+                    println!("vcpu{id}: KVM_EXIT_SHUTDOWN, stopping all vCPUs");
+                    shutdown.store(true, Ordering::Relaxed);
                 }
-                kvm_ioctls::VcpuExit::FailEntry(reason, vcpu) => {
-                    println!("KVM_EXIT_FAIL_ENTRY");
-                    break;
+                kvm_ioctls::VcpuExit::FailEntry(reason, cpu) => {
+                    println!(
+                        "vcpu{id}: KVM_EXIT_FAIL_ENTRY, hardware_entry_failure_reason={reason:#x}, cpu={cpu}"
+                    );
+                    Self::dump_vcpu_state(id, &vcpu);
+                    shutdown.store(true, Ordering::Relaxed);
                 }
-                _ => {
-                    println!("Other exit reason");
-                    break;
+                kvm_ioctls::VcpuExit::InternalError => {
+                    println!("vcpu{id}: KVM_EXIT_INTERNAL_ERROR");
+                    Self::dump_vcpu_state(id, &vcpu);
+                    shutdown.store(true, Ordering::Relaxed);
+                }
+                other => {
+                    println!("vcpu{id}: unhandled exit reason: {other:?}");
+                    shutdown.store(true, Ordering::Relaxed);
                 }
             }
         }
     }
+
+    /// 给每个vCPU起一个线程跑`run_vcpu`，任意一个vCPU请求关机时，其余线程
+    /// 在各自下一次从`vcpu.run()`返回时看到共享的`shutdown`标志并退出，
+    /// 然后在这里统一join
+    fn run(&mut self) {
+        println!("run");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let handles: Vec<_> = std::mem::take(&mut self.vcpus)
+            .into_iter()
+            .enumerate()
+            .map(|(id, vcpu)| {
+                let pio_bus = self.pio_bus.clone();
+                let mmio_bus = self.mmio_bus.clone();
+                let shutdown = shutdown.clone();
+                std::thread::spawn(move || Self::run_vcpu(id, vcpu, pio_bus, mmio_bus, shutdown))
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().expect("vcpu thread panicked");
+        }
+    }
 }
 
 fn main() {
+    // 传一个bzImage路径作为第一个参数就走真正的Linux 64位引导协议；不传
+    // 参数则保留原来的`kernel.bin`实模式演示路径
+    match std::env::args().nth(1) {
+        Some(bzimage) => run_bzimage_guest(PathBuf::from(bzimage)),
+        None => run_real_mode_demo(),
+    }
+}
+
+/// 16位实模式演示路径：直接把`kernel.bin`拍到物理地址0，非交互，串口只
+/// 把输出转发到stdout
+fn run_real_mode_demo() {
     let image = PathBuf::from("./guest_os/kernel.bin");
-    let mut vm = Vm::new();
+    let mut vm = Vm::new(BootMode::Real, 1);
 
     // 设置虚拟机的内存大小1MB
     let mem_size = 0x1000;
-    vm.setup_memory(mem_size);
+    vm.setup_memory(&[GuestRegionDesc {
+        guest_phys_addr: 0,
+        size: mem_size,
+        log_dirty: false,
+    }]);
     vm.setup_cpu();
+    vm.setup_devices(false);
     vm.load_image(image);
     vm.run();
 }
+
+/// 真正的bzImage引导路径：64位长模式，客户机内存留出经典的640KiB-1MiB洞，
+/// 串口桥接host stdin，这样`console=ttyS0`才能真正交互。主内存区域打开
+/// dirty log，跑之前拍一份基线快照，跑完再把脏页增量写回去
+fn run_bzimage_guest(bzimage: PathBuf) {
+    let mut vm = Vm::new(BootMode::Long, 1);
+
+    vm.setup_memory(&[
+        GuestRegionDesc {
+            guest_phys_addr: 0,
+            size: 0xa0000, // 0 - 640KiB
+            log_dirty: false,
+        },
+        GuestRegionDesc {
+            guest_phys_addr: 0x100000,
+            size: 0xf00000, // 1MiB - 16MiB
+            log_dirty: true,
+        },
+    ]);
+    vm.setup_cpu();
+    vm.setup_devices(true);
+    vm.load_kernel(bzimage, None);
+
+    let snapshot_path = PathBuf::from("./guest_os/snapshot.img");
+    vm.snapshot_ram(&snapshot_path);
+    vm.run();
+    vm.snapshot_ram_incremental(&snapshot_path);
+}
@@ -0,0 +1,225 @@
+//! 客户机内存管理：基于`vm-memory`的`GuestMemoryMmap`，取代手写的
+//! `hva_ram_start`指针运算。支持多个不连续的内存区域（比如640KiB-1MiB的
+//! 洞，或者4GiB以上的高端内存），每个区域各自注册为一个递增的KVM内存slot
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
+use kvm_ioctls::VmFd;
+use vm_memory::{
+    Address, Bytes, ByteValued, GuestAddress, GuestMemoryBackend, GuestMemoryMmap,
+    GuestMemoryRegion, MemoryRegionAddress,
+};
+
+/// 4KiB，dirty log以这个粒度标记"脏页"
+const PAGE_SIZE: u64 = 0x1000;
+
+/// 一段客户机物理内存：起始地址+大小，`setup_memory`按这样的列表建内存布局
+#[derive(Debug, Clone, Copy)]
+pub struct GuestRegionDesc {
+    pub guest_phys_addr: u64,
+    pub size: usize,
+    /// 是否给这个slot打开`KVM_MEM_LOG_DIRTY_PAGES`，用于快照/热迁移
+    pub log_dirty: bool,
+}
+
+/// 管理一或多个`GuestRegion`，对外提供按`GuestAddress`寻址、会做边界检查
+/// 的读写接口，以及基于dirty log的快照能力
+pub struct GuestMemoryManager {
+    mem: GuestMemoryMmap,
+    regions: Vec<GuestRegionDesc>,
+}
+
+impl GuestMemoryManager {
+    /// 按给定的区域列表分配客户机内存（每个区域各自mmap一段匿名内存）
+    pub fn new(regions: &[GuestRegionDesc]) -> Self {
+        let ranges: Vec<(GuestAddress, usize)> = regions
+            .iter()
+            .map(|r| (GuestAddress(r.guest_phys_addr), r.size))
+            .collect();
+        let mem = GuestMemoryMmap::from_ranges(&ranges).expect("failed to allocate guest memory");
+        GuestMemoryManager {
+            mem,
+            regions: regions.to_vec(),
+        }
+    }
+
+    /// 把每个区域各自注册为一个KVM内存slot（slot id从0开始递增），
+    /// 按`GuestRegionDesc::log_dirty`决定是否打开`KVM_MEM_LOG_DIRTY_PAGES`
+    pub fn register_with_kvm(&self, vm: &VmFd) {
+        for (slot, region) in self.mem.iter().enumerate() {
+            let desc = self
+                .regions
+                .iter()
+                .find(|d| d.guest_phys_addr == region.start_addr().0)
+                .expect("region has no matching GuestRegionDesc");
+            let userspace_addr = region
+                .get_host_address(MemoryRegionAddress(0))
+                .expect("region has no backing host address") as u64;
+            let flags = if desc.log_dirty {
+                KVM_MEM_LOG_DIRTY_PAGES
+            } else {
+                0
+            };
+            let mem_region = kvm_userspace_memory_region {
+                slot: slot as u32,
+                guest_phys_addr: region.start_addr().0,
+                memory_size: region.len(),
+                userspace_addr,
+                flags,
+            };
+            unsafe {
+                vm.set_user_memory_region(mem_region)
+                    .expect("set_user_memory_region failed");
+            }
+        }
+    }
+
+    /// 取出某个slot的脏页位图（`KVM_GET_DIRTY_LOG`），每个置位的bit对应一个
+    /// 4KiB脏页
+    pub fn get_dirty_log(&self, vm: &VmFd, slot: u32) -> Vec<u64> {
+        let region = self
+            .mem
+            .iter()
+            .nth(slot as usize)
+            .expect("no such memory slot");
+        vm.get_dirty_log(slot, region.len() as usize)
+            .expect("KVM_GET_DIRTY_LOG failed")
+    }
+
+    /// 把全部客户机内存按区域顺序拍到`path`，作为快照的基线
+    pub fn snapshot_ram(&self, path: &Path) {
+        let mut file = File::create(path).expect("failed to create snapshot file");
+        for region in self.mem.iter() {
+            let mut buf = vec![0u8; region.len() as usize];
+            self.mem
+                .read_slice(&mut buf, region.start_addr())
+                .expect("failed to read guest region for snapshot");
+            file.write_all(&buf).expect("failed to write snapshot");
+        }
+    }
+
+    /// 只把dirty log标记过的4KiB页写回已有的快照文件，避免每次都拷贝整个
+    /// RAM；`path`必须是`snapshot_ram`产生的文件
+    pub fn snapshot_ram_incremental(&self, vm: &VmFd, path: &Path) {
+        let mut file = File::options()
+            .write(true)
+            .open(path)
+            .expect("failed to open snapshot file for incremental update");
+
+        let mut file_offset = 0u64;
+        for (slot, region) in self.mem.iter().enumerate() {
+            let desc = self
+                .regions
+                .iter()
+                .find(|d| d.guest_phys_addr == region.start_addr().0)
+                .expect("region has no matching GuestRegionDesc");
+            if !desc.log_dirty {
+                // 这个slot从没打开过KVM_MEM_LOG_DIRTY_PAGES，KVM_GET_DIRTY_LOG
+                // 会直接返回-EINVAL；既然没有跟踪脏页，`snapshot_ram`写的基线
+                // 就是全部，这里无事可做
+                file_offset += region.len();
+                continue;
+            }
+
+            let bitmap = self.get_dirty_log(vm, slot as u32);
+            let mut page_buf = vec![0u8; PAGE_SIZE as usize];
+            for region_offset in dirty_page_offsets(&bitmap, region.len()) {
+                self.mem
+                    .read_slice(
+                        &mut page_buf,
+                        region
+                            .start_addr()
+                            .checked_add(region_offset)
+                            .expect("dirty page offset overflow"),
+                    )
+                    .expect("failed to read dirty page");
+                file.seek(SeekFrom::Start(file_offset + region_offset))
+                    .expect("failed to seek snapshot file");
+                file.write_all(&page_buf)
+                    .expect("failed to write dirty page");
+            }
+            file_offset += region.len();
+        }
+    }
+
+    /// 底层的`GuestMemoryMmap`，需要直接喂给`linux-loader`等只认
+    /// `GuestMemory`的API时使用
+    pub fn inner(&self) -> &GuestMemoryMmap {
+        &self.mem
+    }
+
+    /// 把`val`按位写到`addr`处，越界时返回`Err`
+    pub fn write_obj<T: ByteValued>(&self, val: T, addr: GuestAddress) -> std::io::Result<()> {
+        self.mem
+            .write_obj(val, addr)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))
+    }
+
+    pub fn write_slice(&self, data: &[u8], addr: GuestAddress) -> std::io::Result<()> {
+        self.mem
+            .write_slice(data, addr)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))
+    }
+
+    /// 从`addr`处按位读出一个`T`，越界时返回`Err`
+    pub fn read_obj<T: ByteValued>(&self, addr: GuestAddress) -> std::io::Result<T> {
+        self.mem
+            .read_obj(addr)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))
+    }
+
+    pub fn read_slice(&self, buf: &mut [u8], addr: GuestAddress) -> std::io::Result<()> {
+        self.mem
+            .read_slice(buf, addr)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))
+    }
+}
+
+/// 把`KVM_GET_DIRTY_LOG`返回的位图翻译成这个区域内每个脏4KiB页相对区域
+/// 起点的字节偏移；`region_len`之外（位图按64位字对齐，末尾可能多出几个
+/// bit）的脏位会被丢弃
+fn dirty_page_offsets(bitmap: &[u64], region_len: u64) -> Vec<u64> {
+    let mut offsets = Vec::new();
+    for (word_idx, word) in bitmap.iter().enumerate() {
+        for bit in 0..64 {
+            if word & (1 << bit) == 0 {
+                continue;
+            }
+            let page_idx = word_idx as u64 * 64 + bit as u64;
+            let offset = page_idx * PAGE_SIZE;
+            if offset < region_len {
+                offsets.push(offset);
+            }
+        }
+    }
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_dirty_bits_yields_no_pages() {
+        assert_eq!(dirty_page_offsets(&[0, 0], 8 * PAGE_SIZE), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn finds_pages_across_word_boundaries() {
+        // 第0个word的bit0和bit63，第1个word的bit0 -> 页号0, 63, 64
+        let bitmap = [1u64 | (1 << 63), 1u64];
+        let offsets = dirty_page_offsets(&bitmap, 128 * PAGE_SIZE);
+        assert_eq!(offsets, vec![0, 63 * PAGE_SIZE, 64 * PAGE_SIZE]);
+    }
+
+    #[test]
+    fn drops_pages_past_the_region_length() {
+        // bit0和bit1都置位，但区域只有一页大，bit1对应的页应该被丢弃
+        let bitmap = [0b11u64];
+        let offsets = dirty_page_offsets(&bitmap, PAGE_SIZE);
+        assert_eq!(offsets, vec![0]);
+    }
+}
@@ -0,0 +1,243 @@
+//! 8250/16550 UART仿真，绑定在COM1（0x3f8-0x3ff），这样`console=ttyS0`才能
+//! 真正给客户机一个可交互的控制台，而不是把`IoOut`字节硬当UTF-8打印
+
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use crate::bus::Device;
+
+// 寄存器偏移，相对于COM1基址0x3f8（DLAB=0时）
+const REG_THR_RBR: u64 = 0; // 发送保持寄存器/接收缓冲寄存器
+const REG_IER: u64 = 1; // 中断使能寄存器
+const REG_IIR_FCR: u64 = 2; // 中断标识/FIFO控制寄存器
+const REG_LCR: u64 = 3; // 线路控制寄存器
+const REG_MCR: u64 = 4; // 调制解调器控制寄存器
+const REG_LSR: u64 = 5; // 线路状态寄存器
+const REG_MSR: u64 = 6; // 调制解调器状态寄存器
+
+const LSR_RX_READY: u8 = 1 << 0;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+const LSR_TX_IDLE: u8 = 1 << 6;
+
+/// LCR bit 7：DLAB置位时，偏移0/1不再是THR/RBR和IER，而是改成波特率
+/// 除数锁存器的低/高字节（DLL/DLM）
+const LCR_DLAB: u8 = 1 << 7;
+
+/// COM1串口设备，THR写入直接转发到stdout。默认不连接任何输入源，RX FIFO
+/// 一直是空的；只有调用`with_stdin_bridge`之后才会把host stdin桥接进来
+pub struct Serial {
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    /// DLAB置位时偏移0/1写入的波特率除数锁存器；我们不真正模拟波特率，
+    /// 只是如实存住这两个字节，这样Linux配置串口时不会把它们误当成
+    /// THR/IER处理
+    dll: u8,
+    dlm: u8,
+    rx_fifo: Arc<Mutex<VecDeque<u8>>>,
+    /// 桥接stdin之前保存下来的原始termios，`Drop`时用它把host终端复原；
+    /// 从没开启过桥接的话是`None`，不会去碰host的终端
+    saved_termios: Option<libc::termios>,
+}
+
+impl Serial {
+    /// 创建一个不连接任何输入源的串口：适合`kernel.bin`这类非交互场景，
+    /// 不会动host的终端设置
+    pub fn new() -> Self {
+        Serial {
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            dll: 0,
+            dlm: 0,
+            rx_fifo: Arc::new(Mutex::new(VecDeque::new())),
+            saved_termios: None,
+        }
+    }
+
+    /// 额外把host标准输入桥接到这个串口的RX FIFO：保存原始termios、把
+    /// stdin切到raw模式，再起一个线程喂数据。只应该在确实交互式运行（比如
+    /// 真正的bzImage配上`console=ttyS0`）时调用；原始termios会在这个
+    /// `Serial`被丢弃时自动复原
+    pub fn with_stdin_bridge(mut self) -> Self {
+        self.saved_termios = Some(enable_stdin_raw_mode());
+        spawn_stdin_reader(self.rx_fifo.clone());
+        self
+    }
+
+    #[cfg(test)]
+    fn push_rx_byte(&self, byte: u8) {
+        self.rx_fifo.lock().unwrap().push_back(byte);
+    }
+}
+
+impl Drop for Serial {
+    fn drop(&mut self) {
+        if let Some(term) = self.saved_termios {
+            restore_stdin_mode(&term);
+        }
+    }
+}
+
+impl Device for Serial {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let dlab = self.lcr & LCR_DLAB != 0;
+        data[0] = match offset {
+            REG_THR_RBR if dlab => self.dll,
+            REG_IER if dlab => self.dlm,
+            REG_THR_RBR => self.rx_fifo.lock().unwrap().pop_front().unwrap_or(0),
+            REG_IER => self.ier,
+            REG_IIR_FCR => 0xc1, // 无挂起中断，FIFO已使能
+            REG_LCR => self.lcr,
+            REG_MCR => self.mcr,
+            REG_LSR => {
+                let rx_ready = !self.rx_fifo.lock().unwrap().is_empty();
+                LSR_TX_EMPTY | LSR_TX_IDLE | if rx_ready { LSR_RX_READY } else { 0 }
+            }
+            REG_MSR => 0,
+            _ => 0,
+        };
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let val = data[0];
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            REG_THR_RBR if dlab => self.dll = val,
+            REG_IER if dlab => self.dlm = val,
+            REG_THR_RBR => {
+                print!("{}", val as char);
+                use std::io::Write;
+                std::io::stdout().flush().ok();
+            }
+            REG_IER => self.ier = val,
+            REG_IIR_FCR => {} // FCR：暂不模拟FIFO复位
+            REG_LCR => self.lcr = val,
+            REG_MCR => self.mcr = val,
+            _ => {}
+        }
+    }
+}
+
+/// 把host的标准输入切到raw模式，这样按键能一字节一字节地透传给客户机；
+/// 返回切换前的termios，调用方负责之后把它还原
+fn enable_stdin_raw_mode() -> libc::termios {
+    unsafe {
+        let mut original: libc::termios = std::mem::zeroed();
+        libc::tcgetattr(libc::STDIN_FILENO, &mut original);
+        let mut raw = original;
+        libc::cfmakeraw(&mut raw);
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw);
+        original
+    }
+}
+
+/// 把host标准输入的termios恢复成`enable_stdin_raw_mode`之前保存的样子
+fn restore_stdin_mode(term: &libc::termios) {
+    unsafe {
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, term);
+    }
+}
+
+/// 起一个线程不断从stdin读取字节，塞进RX FIFO供`REG_THR_RBR`读取
+fn spawn_stdin_reader(rx_fifo: Arc<Mutex<VecDeque<u8>>>) {
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut byte = [0u8; 1];
+        while let Ok(1) = stdin.read(&mut byte) {
+            rx_fifo.lock().unwrap().push_back(byte[0]);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lsr_reports_tx_always_ready_and_rx_only_when_data_pending() {
+        let mut serial = Serial::new();
+        let mut lsr = [0u8; 1];
+
+        serial.read(REG_LSR, &mut lsr);
+        assert_eq!(lsr[0], LSR_TX_EMPTY | LSR_TX_IDLE);
+
+        serial.push_rx_byte(b'A');
+        serial.read(REG_LSR, &mut lsr);
+        assert_eq!(lsr[0], LSR_TX_EMPTY | LSR_TX_IDLE | LSR_RX_READY);
+    }
+
+    #[test]
+    fn rbr_drains_the_rx_fifo_in_order() {
+        let mut serial = Serial::new();
+        serial.push_rx_byte(b'h');
+        serial.push_rx_byte(b'i');
+
+        let mut byte = [0u8; 1];
+        serial.read(REG_THR_RBR, &mut byte);
+        assert_eq!(byte[0], b'h');
+        serial.read(REG_THR_RBR, &mut byte);
+        assert_eq!(byte[0], b'i');
+        // FIFO空了之后，再读到的是0而不是上一个字节
+        serial.read(REG_THR_RBR, &mut byte);
+        assert_eq!(byte[0], 0);
+    }
+
+    #[test]
+    fn iir_fcr_always_reports_fifo_enabled_no_pending_interrupt() {
+        let mut serial = Serial::new();
+        let mut iir = [0u8; 1];
+        serial.read(REG_IIR_FCR, &mut iir);
+        assert_eq!(iir[0], 0xc1);
+    }
+
+    #[test]
+    fn new_serial_does_not_touch_stdin_termios() {
+        // 没调用`with_stdin_bridge`就不应该保存termios，也就不会在Drop时
+        // 去碰host的终端设置
+        let serial = Serial::new();
+        assert!(serial.saved_termios.is_none());
+    }
+
+    #[test]
+    fn dlab_set_routes_offsets_0_and_1_to_the_divisor_latch() {
+        let mut serial = Serial::new();
+        serial.write(REG_LCR, &[LCR_DLAB]);
+        serial.write(REG_THR_RBR, &[0x01]);
+        serial.write(REG_IER, &[0x00]);
+
+        let mut byte = [0u8; 1];
+        serial.read(REG_THR_RBR, &mut byte);
+        assert_eq!(byte[0], 0x01);
+        serial.read(REG_IER, &mut byte);
+        assert_eq!(byte[0], 0x00);
+        // IER本身不应该被DLAB写入动到
+        assert_eq!(serial.ier, 0);
+    }
+
+    #[test]
+    fn dlab_clear_leaves_thr_rbr_and_ier_untouched_by_divisor_latch() {
+        let mut serial = Serial::new();
+        // 先在DLAB=1时设置除数锁存器
+        serial.write(REG_LCR, &[LCR_DLAB]);
+        serial.write(REG_THR_RBR, &[0x01]);
+        serial.write(REG_IER, &[0x00]);
+        // 回到DLAB=0，offset 0/1应该恢复成RBR/IER的老行为
+        serial.write(REG_LCR, &[0]);
+        serial.push_rx_byte(b'x');
+        serial.write(REG_IER, &[0x0f]);
+
+        let mut byte = [0u8; 1];
+        serial.read(REG_THR_RBR, &mut byte);
+        assert_eq!(byte[0], b'x');
+        serial.read(REG_IER, &mut byte);
+        assert_eq!(byte[0], 0x0f);
+    }
+}